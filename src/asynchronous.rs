@@ -0,0 +1,42 @@
+//! `embedded-hal-async` flavor of the driver, gated behind the `async`
+//! feature. Mirrors the blocking [`HRS3300`](crate::HRS3300) API so it can
+//! be polled cooperatively alongside other devices (e.g. a touch panel or
+//! accelerometer) on the same async I2C bus.
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::common;
+use crate::common::{decode_raw_sample, AmbientLightType, Error, HeartRateType, ReflectedLightType};
+use crate::definitions::{
+    AdcResolution, EnableRegField, HgainRegField, HrsGain, LedCurrent, PDriverRegField, Register,
+    SlaveAddr, WaitTime,
+};
+use crate::macros::impl_hrs3300_core;
+use crate::ppg::PpgPipeline;
+
+/// Async counterpart of [`HRS3300`](crate::HRS3300), built on
+/// `embedded_hal_async::i2c::I2c`.
+#[derive(Debug)]
+pub struct HRS3300Async<I2C> {
+    i2c_port: I2C,
+    address: u8,
+    /// The selected bits of resolution of the ADC
+    adc_resolution: AdcResolution,
+    resolution_mask: u32,
+    /// Fixed cadence at which the caller drives `sample_one`, used to
+    /// convert inter-peak sample counts into BPM
+    sample_rate_hz: f32,
+    ppg: PpgPipeline,
+    /// Coefficient `k` used to subtract the ambient-light (C1) contribution
+    /// from the HRS reflectance (C0): `compensated = c0 - k * c1`
+    ambient_coefficient: f32,
+}
+
+impl_hrs3300_core!(
+    HRS3300Async,
+    [I2C],
+    [I2C: I2c,],
+    I2C::Error,
+    [async],
+    [.await]
+);
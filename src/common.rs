@@ -0,0 +1,104 @@
+//! Types and register-decoding logic shared between the blocking and async
+//! (`async` feature) flavors of the driver.
+
+/// Errors in this crate
+#[derive(Debug)]
+pub enum Error<CommE> {
+    Comm(CommE),
+
+    /// The device ID read from the device is unrecognized
+    DeviceId,
+}
+
+pub type HeartRateType = u16;
+
+pub type LuminanceType = u32;
+pub type AmbientLightType = LuminanceType;
+pub type ReflectedLightType = LuminanceType;
+
+pub const SAMPLE_BLOCK_LEN: usize = 7;
+
+pub const DEFAULT_DEVICE_ADDRESS: u8 = 0x44;
+pub const DEFAULT_DEVICE_ID: u8 = 0x21;
+
+/// Default sampling cadence assumed by the heart-rate pipeline, matching
+/// the 50ms polling interval used on PineTime
+pub const DEFAULT_SAMPLE_RATE_HZ: f32 = 20.0;
+
+/// recommended value of reserved RES register bits
+pub const RESERVED_RESOLUTION_BITS: u8 = 0x60;
+/// recommended value of reserved ENABLE register bits
+pub const RESERVED_ENABLE_BITS: u8 = 0x60;
+/// recommended value of reserved PDRIVE register bits
+pub const RESERVED_PDRIVE_BITS: u8 = 0x08;
+
+/// Default ambient-compensation coefficient `k` in `c0 - k * c1`, derived
+/// from the ratio observed in the charger-strap sample data in `lib.rs` (C0 ~5,
+/// C1 ~82500) where the reflectance signal carries no pulsatile component
+/// and should compensate to roughly zero.
+pub const DEFAULT_AMBIENT_COEFFICIENT: f32 = 0.0000606;
+
+/// Fraction of the ADC full-scale (derived from `resolution_mask`) above
+/// which the ambient (C1) channel is considered saturated/overexposed.
+pub const AMBIENT_SATURATION_FRACTION: f32 = 0.95;
+
+/// Reassemble the C0 (HRS reflectance) and C1 (ambient light) channels from
+/// a raw register block read starting at `C1DATAM`.
+/// The order of `block` is:
+/// 0: C1DATAM 0x08
+/// 1: C0DATAM 0x09
+/// 2: C0DATAH 0x0A
+/// 3: PDRIVER
+/// 4: C1DATAH 0x0D
+/// 5: C1DATAL 0x0E
+/// 6: C0DATAL 0x0F
+pub fn decode_raw_sample(
+    block: &[u8; SAMPLE_BLOCK_LEN],
+    resolution_mask: u32,
+) -> (ReflectedLightType, AmbientLightType) {
+    let mut c1: u32 = (block[0] as u32) << 3; // 7:0 -> C1DATA[10:3]
+    c1 |= ((block[4] & 0x7F) as u32) << 11; // 6:0 -> C1DATA[17:11]
+    c1 |= (block[5] & 0x07) as u32; // 2:0 -> C1DATA[2:0]
+    c1 &= resolution_mask;
+
+    let mut c0: u32 = (block[1] as u32) << 8; // 7:0 -> C0DATA[15:8]
+    c0 |= ((block[2] & 0x0F) as u32) << 4; // 3:0 -> C0DATA[7:4]
+    c0 |= ((block[6] & 0x30) as u32) << 12; // 5:4 -> C0DATA[17:16]
+    c0 |= (block[6] & 0x0F) as u32; // 3:0 -> C0DATA[3:0]
+    c0 &= resolution_mask;
+
+    // c0 is HRS reflectance
+    // c1 is ambient light sensor (luminance)
+    (c0 as ReflectedLightType, c1 as AmbientLightType)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // C1DATAM, C0DATAM, C0DATAH, PDRIVER, C1DATAH, C1DATAL, C0DATAL
+    const BLOCK: [u8; SAMPLE_BLOCK_LEN] = [0xFF, 0xAA, 0x0F, 0x00, 0x3F, 0x07, 0x3F];
+
+    #[test]
+    fn reassembles_the_scattered_channel_bits() {
+        let full_scale_mask = (1u32 << 18) - 1;
+        let (c0, c1) = decode_raw_sample(&BLOCK, full_scale_mask);
+        assert_eq!(c0, 0x3AAFF);
+        assert_eq!(c1, 0x1FFFF);
+    }
+
+    #[test]
+    fn truncates_to_the_configured_adc_resolution() {
+        // 14-bit resolution: only the low 14 bits of each channel survive.
+        let resolution_mask = (1u32 << 14) - 1;
+        let (c0, c1) = decode_raw_sample(&BLOCK, resolution_mask);
+        assert_eq!(c0, 0xAAFF & resolution_mask);
+        assert_eq!(c1, 0x1FFFF & resolution_mask);
+    }
+
+    #[test]
+    fn zero_block_decodes_to_zero() {
+        let (c0, c1) = decode_raw_sample(&[0; SAMPLE_BLOCK_LEN], (1u32 << 18) - 1);
+        assert_eq!((c0, c1), (0, 0));
+    }
+}
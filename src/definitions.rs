@@ -1,3 +1,47 @@
+/// A validated 7-bit I2C slave address.
+///
+/// The HRS3300 only ever responds to a 7-bit address; passing an 8-bit
+/// (pre-shifted) address to an I2C peripheral is a common bring-up mistake.
+/// `SlaveAddr` masks off bit 7 on construction so that mistake can't
+/// silently reach the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlaveAddr(u8);
+
+impl SlaveAddr {
+    const ADDRESS_MASK: u8 = 0x7F;
+
+    /// Construct a `SlaveAddr` from a 7-bit address, masking off bit 7 so an
+    /// accidentally 8-bit address can't silently be used.
+    pub fn new(addr: u8) -> Self {
+        Self(addr & Self::ADDRESS_MASK)
+    }
+
+    /// The validated 7-bit address value.
+    pub fn addr(self) -> u8 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_through_a_7_bit_address_unchanged() {
+        assert_eq!(SlaveAddr::new(0x44).addr(), 0x44);
+        assert_eq!(SlaveAddr::new(0x7F).addr(), 0x7F);
+        assert_eq!(SlaveAddr::new(0x00).addr(), 0x00);
+    }
+
+    #[test]
+    fn masks_off_bit_7_of_an_accidentally_8_bit_address() {
+        // 0x44 shifted left one bit, as if the caller passed an already
+        // 8-bit-shifted address — bit 7 must be dropped.
+        assert_eq!(SlaveAddr::new(0x88).addr(), 0x08);
+        assert_eq!(SlaveAddr::new(0xFF).addr(), 0x7F);
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum AdcResolution {
@@ -69,3 +113,43 @@ pub enum HgainRegField {
     /// HRS gain
     HGAIN = 0b111 << 2,
 }
+
+/// LED drive current. The 2-bit field is split across `PDRIVE0` (bit 6 of
+/// the PDRIVER register) and `PDRIVE1` (bit 3 of the ENABLE register).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum LedCurrent {
+    Ma12_5 = 0b00,
+    Ma20 = 0b01,
+    Ma30 = 0b10,
+    Ma40 = 0b11,
+}
+
+/// HRS sensor gain, the `HGAIN` field (bits 4:2) of the HGAIN register (0x17).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum HrsGain {
+    X1 = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+    X32 = 5,
+    X64 = 6,
+    X128 = 7,
+}
+
+/// HRS wait time between samples, the `HWT` field (bits 6:4) of the ENABLE
+/// register (0x01).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum WaitTime {
+    Ms800 = 0b000,
+    Ms400 = 0b001,
+    Ms200 = 0b010,
+    Ms100 = 0b011,
+    Ms75 = 0b100,
+    Ms50 = 0b101,
+    Ms12_5 = 0b110,
+    Ms0 = 0b111,
+}
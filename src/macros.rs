@@ -0,0 +1,298 @@
+//! Shared implementation of the high-level driver API, used to generate
+//! both the blocking [`HRS3300`](crate::HRS3300) and (behind the `async`
+//! feature) async `HRS3300Async` without hand-keeping two copies in sync.
+//!
+//! The two flavors differ only in whether I2C calls are awaited, so the
+//! body is written once here and instantiated per flavor by splicing in an
+//! `async` keyword and a `.await` as needed.
+
+// Paths referenced in the macro body below (`Register`, `Error`, `common::*`,
+// `PpgPipeline`, etc.) are resolved at each invocation site, not here — every
+// module that invokes `impl_hrs3300_core!` must bring them into scope itself.
+
+macro_rules! impl_hrs3300_core {
+    (
+        $name:ident,
+        [$($generics:tt)*],
+        [$($where_preds:tt)*],
+        $err_ty:ty,
+        [$($asyncness:tt)*],
+        [$($dot_await:tt)*]
+    ) => {
+        impl<$($generics)*> $name<I2C>
+        where
+            $($where_preds)*
+        {
+            pub const DEFAULT_DEVICE_ADDRESS: u8 = common::DEFAULT_DEVICE_ADDRESS;
+            const DEFAULT_DEVICE_ID: u8 = common::DEFAULT_DEVICE_ID;
+
+            /// Default sampling cadence assumed by the heart-rate pipeline, matching
+            /// the 50ms polling interval used on PineTime
+            pub const DEFAULT_SAMPLE_RATE_HZ: f32 = common::DEFAULT_SAMPLE_RATE_HZ;
+
+            /// recommended value of reserved RES register bits
+            const RESERVED_RESOLUTION_BITS: u8 = common::RESERVED_RESOLUTION_BITS;
+            /// recommended value of reserved ENABLE register bits
+            const RESERVED_ENABLE_BITS: u8 = common::RESERVED_ENABLE_BITS;
+            /// recommended value of reserved PDRIVE register bits
+            const RESERVED_PDRIVE_BITS: u8 = common::RESERVED_PDRIVE_BITS;
+
+            fn new(
+                i2c_port: I2C,
+                address: u8,
+                adc_resolution: AdcResolution,
+                sample_rate_hz: f32,
+            ) -> Self {
+                Self {
+                    i2c_port,
+                    address,
+                    adc_resolution,
+                    resolution_mask: 0,
+                    sample_rate_hz,
+                    ppg: PpgPipeline::new(sample_rate_hz),
+                    ambient_coefficient: common::DEFAULT_AMBIENT_COEFFICIENT,
+                }
+            }
+
+            pub fn default(i2c_port: I2C) -> Self {
+                Self::new_with_address(i2c_port, SlaveAddr::new(Self::DEFAULT_DEVICE_ADDRESS))
+            }
+
+            /// Construct at the given 7-bit slave address. See
+            /// [`SlaveAddr`] for why the address is validated rather than a
+            /// bare `u8`.
+            pub fn new_with_address(i2c_port: I2C, address: SlaveAddr) -> Self {
+                Self::new(
+                    i2c_port,
+                    address.addr(),
+                    AdcResolution::Bits14,
+                    Self::DEFAULT_SAMPLE_RATE_HZ,
+                )
+            }
+
+            pub $($asyncness)* fn init(&mut self) -> Result<(), Error<$err_ty>> {
+                //first verify we can get a device ID
+                let device_id = self.get_device_id()$($dot_await)*?;
+                if device_id != Self::DEFAULT_DEVICE_ID {
+                    return Err(Error::DeviceId);
+                }
+
+                // There are only four writable registers we need to set:
+                // PDRIVER
+                // RES
+                // HGAIN
+                // ENABLE
+                // Power on the LED oscillator and the HRS sensor first, then compose
+                // the finer-grained fields on top via the typed setters below.
+                self.write_register(
+                    Register::PDRIVER,
+                    (PDriverRegField::PON as u8) | Self::RESERVED_PDRIVE_BITS,
+                )$($dot_await)*?;
+                //flush resolution setting to the sensor
+                self.set_adc_resolution(self.adc_resolution)$($dot_await)*?;
+                self.write_register(
+                    Register::ENABLE,
+                    (EnableRegField::HEN as u8) | Self::RESERVED_ENABLE_BITS,
+                )$($dot_await)*?;
+
+                self.set_led_current(LedCurrent::Ma40)$($dot_await)*?; // datasheet-recommended default
+                self.set_hrs_gain(HrsGain::X16)$($dot_await)*?; // datasheet-recommended default
+                self.set_wait_time(WaitTime::Ms12_5)$($dot_await)*?; // datasheet-recommended default
+
+                Ok(())
+            }
+
+            pub $($asyncness)* fn enable(&mut self, enable: bool) -> Result<(), Error<$err_ty>> {
+                let enable_val = Self::RESERVED_ENABLE_BITS;
+                let enable_val = if enable {
+                    enable_val | (EnableRegField::HEN as u8)
+                } else {
+                    enable_val & !(EnableRegField::HEN as u8)
+                };
+                self.write_register(Register::ENABLE, enable_val)$($dot_await)*?;
+
+                let pdrive_val = Self::RESERVED_PDRIVE_BITS;
+                let pdrive_val = if enable {
+                    pdrive_val | (PDriverRegField::PON as u8)
+                } else {
+                    pdrive_val & !(PDriverRegField::PON as u8)
+                };
+                self.write_register(Register::PDRIVER, pdrive_val)$($dot_await)*?;
+
+                Ok(())
+            }
+
+            /// The fixed sampling cadence, in Hz, the heart-rate pipeline assumes
+            /// the caller is driving `sample_one` at
+            pub fn sample_rate_hz(&self) -> f32 {
+                self.sample_rate_hz
+            }
+
+            pub $($asyncness)* fn get_device_id(&mut self) -> Result<u8, Error<$err_ty>> {
+                let device_id = self.read_register(Register::ID)$($dot_await)*?;
+                Ok(device_id)
+            }
+
+            pub $($asyncness)* fn set_adc_resolution(
+                &mut self,
+                resolution: AdcResolution,
+            ) -> Result<(), Error<$err_ty>> {
+                self.adc_resolution = resolution;
+                self.resolution_mask = (1 << (8 + (self.adc_resolution as u32))) - 1;
+
+                let resolution_reg_val = (self.adc_resolution as u8) | Self::RESERVED_RESOLUTION_BITS; // 0x66 rec
+                self.write_register(Register::RES, resolution_reg_val)$($dot_await)*
+            }
+
+            /// Set the LED drive current. The 2-bit value is split across the
+            /// `PDRIVE0` bit of the PDRIVER register and the `PDRIVE1` bit of the
+            /// ENABLE register; both are updated with a read-modify-write that
+            /// preserves the rest of each register.
+            pub $($asyncness)* fn set_led_current(&mut self, current: LedCurrent) -> Result<(), Error<$err_ty>> {
+                let raw = current as u8;
+                let pdrive0_bit = (raw & 0b01) << 6;
+                let pdrive1_bit = ((raw & 0b10) >> 1) << 3;
+
+                let pdriver_val = self.read_register(Register::PDRIVER)$($dot_await)*?;
+                let pdriver_val = (pdriver_val & !(PDriverRegField::PDRIVE0 as u8)) | pdrive0_bit;
+                self.write_register(Register::PDRIVER, pdriver_val)$($dot_await)*?;
+
+                let enable_val = self.read_register(Register::ENABLE)$($dot_await)*?;
+                let enable_val = (enable_val & !(EnableRegField::PDRIVE1 as u8)) | pdrive1_bit;
+                self.write_register(Register::ENABLE, enable_val)$($dot_await)*
+            }
+
+            /// Set the HRS sensor gain (the `HGAIN` field of the HGAIN register),
+            /// preserving the rest of the register.
+            pub $($asyncness)* fn set_hrs_gain(&mut self, gain: HrsGain) -> Result<(), Error<$err_ty>> {
+                let hgain_val = self.read_register(Register::HGAIN)$($dot_await)*?;
+                let hgain_val = (hgain_val & !(HgainRegField::HGAIN as u8)) | ((gain as u8) << 2);
+                self.write_register(Register::HGAIN, hgain_val)$($dot_await)*
+            }
+
+            /// Set the HRS wait time between samples (the `HWT` field of the ENABLE
+            /// register), preserving the rest of the register.
+            pub $($asyncness)* fn set_wait_time(&mut self, wait: WaitTime) -> Result<(), Error<$err_ty>> {
+                let enable_val = self.read_register(Register::ENABLE)$($dot_await)*?;
+                let enable_val = (enable_val & !(EnableRegField::HWT as u8)) | ((wait as u8) << 4);
+                self.write_register(Register::ENABLE, enable_val)$($dot_await)*
+            }
+
+            /// Set the coefficient `k` used to subtract the ambient-light (C1)
+            /// contribution from the HRS reflectance (C0): `compensated = c0 - k *
+            /// c1`. Defaults to a value derived from a no-pulsatile-signal
+            /// reference sample; recalibrate if the sensor's optical stack differs.
+            pub fn set_ambient_coefficient(&mut self, k: f32) {
+                self.ambient_coefficient = k;
+            }
+
+            /// Whether the ambient (C1) channel is at or near saturation, derived
+            /// from `resolution_mask`. A saturated ambient channel means bright
+            /// ambient light is swamping the sensor and compensated readings may be
+            /// unreliable.
+            pub fn is_ambient_saturated(&self, ambient: AmbientLightType) -> bool {
+                ambient as f32 > self.resolution_mask as f32 * common::AMBIENT_SATURATION_FRACTION
+            }
+
+            /// Read a sample and apply ambient-light compensation using the C1
+            /// (ambient) channel: `compensated = c0 - k * c1`. Returns the
+            /// compensated reflectance along with a flag indicating the ambient
+            /// channel is near saturation, in which case the compensated value may
+            /// be unreliable.
+            pub $($asyncness)* fn read_compensated_sample(&mut self) -> Result<(f32, bool), Error<$err_ty>> {
+                let (c0, c1) = self.read_raw_sample()$($dot_await)*?;
+                let compensated = c0 as f32 - self.ambient_coefficient * c1 as f32;
+                Ok((compensated, self.is_ambient_saturated(c1)))
+            }
+
+            /// Ambient-compensate a raw sample for consumption by the heart-rate
+            /// pipeline, clamped to a non-negative reflectance.
+            fn compensated_raw(&self, c0: ReflectedLightType, c1: AmbientLightType) -> u32 {
+                let compensated = c0 as f32 - self.ambient_coefficient * c1 as f32;
+                if compensated > 0.0 {
+                    compensated as u32
+                } else {
+                    0
+                }
+            }
+
+            /// Read a sample from the sensors,
+            /// store it in a local time series sample buffer,
+            /// and process it into a heart rate measurement if possible.
+            /// Returns a heart rate measurement if one can be estimated
+            /// from the time-series data available.
+            pub $($asyncness)* fn sample_one(&mut self) -> Result<Option<HeartRateType>, Error<$err_ty>> {
+                let (c0, c1) = self.read_raw_sample()$($dot_await)*?;
+                let compensated = self.compensated_raw(c0, c1);
+                Ok(self.ppg.push_sample(compensated))
+            }
+
+            /// Read a sample and process it into a heart rate measurement, canceling
+            /// the motion-correlated component of the signal using an external
+            /// accelerometer magnitude reference (e.g. from a BMA421 on the same
+            /// bus). Use this instead of `sample_one` when a motion reference is
+            /// available, to keep a usable pulse during wrist movement.
+            pub $($asyncness)* fn sample_one_with_motion(
+                &mut self,
+                accel_mag: i32,
+            ) -> Result<Option<HeartRateType>, Error<$err_ty>> {
+                let (c0, c1) = self.read_raw_sample()$($dot_await)*?;
+                let compensated = self.compensated_raw(c0, c1);
+                Ok(self.ppg.push_sample_with_motion(compensated, accel_mag))
+            }
+
+            /// Read a raw sample from the sensors
+            /// Returns `(HRS, ALS)` where:
+            /// - HRS has units of the reflected light type,
+            /// - ALS has units of the ambient light
+            ///
+            /// These units are undocumented but we assume they're the same (luminance or equivalent)
+            pub $($asyncness)* fn read_raw_sample(
+                &mut self,
+            ) -> Result<(ReflectedLightType, AmbientLightType), Error<$err_ty>> {
+                let block = self.read_sample_block()$($dot_await)*?;
+                Ok(decode_raw_sample(&block, self.resolution_mask))
+            }
+
+            /// Read the multiple registers needed to form a complete sample
+            /// The order returned is:
+            /// C1DATAM = 0x08, 7:0 -> C1DATA[10:3]
+            /// C0DATAM = 0x09, 7:0 -> C0DATA[15:8]
+            /// C0DATAH = 0x0A, 3:0 -> C0DATA[7:4]
+            /// PDRIVER = 0x0C,
+            /// C1DATAH = 0x0D, 6:0 -> C1DATA[17:11]
+            /// C1DATAL = 0x0E, 2:0 -> C1DATA[2:0]
+            /// C0DATAL = 0x0F, 5:4 -> C0DATA[17:16], 3:0 -> C0DATA[3:0]
+            $($asyncness)* fn read_sample_block(&mut self) -> Result<[u8; common::SAMPLE_BLOCK_LEN], Error<$err_ty>> {
+                let mut sample_buf = [0u8; common::SAMPLE_BLOCK_LEN];
+                // read multiple registers starting at C1DATAM
+                self.read_registers(Register::C1DATAM, sample_buf.as_mut())$($dot_await)*?;
+                Ok(sample_buf)
+            }
+
+            $($asyncness)* fn read_register(&mut self, register: Register) -> Result<u8, Error<$err_ty>> {
+                let mut data = [0];
+                self.read_registers(register, data.as_mut())$($dot_await)*?;
+                Ok(data[0])
+            }
+
+            $($asyncness)* fn write_register(&mut self, register: Register, value: u8) -> Result<(), Error<$err_ty>> {
+                self.i2c_port
+                    .write(self.address, &[register as u8, value])
+                    $($dot_await)*
+                    .map_err(Error::Comm)
+            }
+
+            /// Read one or more registers at once, beginning at the start register
+            $($asyncness)* fn read_registers(&mut self, start: Register, buf: &mut [u8]) -> Result<(), Error<$err_ty>> {
+                self.i2c_port
+                    .write_read(self.address, &[start as u8], buf)
+                    $($dot_await)*
+                    .map_err(Error::Comm)?;
+                Ok(())
+            }
+        }
+    };
+}
+
+pub(crate) use impl_hrs3300_core;
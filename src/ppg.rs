@@ -0,0 +1,405 @@
+//! Allocation-free PPG (photoplethysmography) signal processing.
+//!
+//! Turns a stream of raw C0 reflectance samples into a heart-rate estimate:
+//! baseline/DC removal, band-limiting low-pass filtering, adaptive peak
+//! detection, and BPM conversion from the median of recent inter-peak
+//! intervals. All state is fixed-size so it lives inline in `HRS3300` with
+//! no heap involved.
+
+/// Length of the raw-sample ring buffer. The slow-moving DC/ambient baseline
+/// is the mean over the whole ring, so at the default 20 Hz sample rate the
+/// baseline window is 3.2 s.
+const RING_LEN: usize = 64;
+
+/// Number of recent inter-peak intervals kept for the BPM median.
+const MAX_INTERVALS: usize = 4;
+
+/// Single-pole low-pass coefficient applied after baseline removal, to
+/// suppress high-frequency noise. At the default 20 Hz sample rate its
+/// -3 dB corner is about 1.1 Hz, so a 2 Hz (120 BPM) pulse is attenuated by
+/// about 6 dB; the corner scales with the sample rate.
+const LOWPASS_ALPHA: f32 = 0.3;
+
+/// Fraction of the recent signal envelope a sample must exceed to be
+/// considered a candidate peak.
+const PEAK_THRESHOLD_FRACTION: f32 = 0.6;
+
+/// Per-sample decay applied to the envelope (peak amplitude) estimate.
+const ENVELOPE_DECAY: f32 = 0.98;
+
+/// Minimum time between accepted peaks, rejects double-detections on a
+/// single beat.
+const REFRACTORY_MS: f32 = 300.0;
+
+const MIN_BPM: f32 = 30.0;
+const MAX_BPM: f32 = 240.0;
+
+/// Minimum number of consistent inter-peak intervals before a BPM value is
+/// reported.
+const MIN_CONSISTENT_INTERVALS: usize = 3;
+
+/// Maximum fraction by which any interval may deviate from the median
+/// before the set is rejected as inconsistent (e.g. a spurious
+/// threshold-crossing during signal settling, or a motion event the
+/// canceller didn't fully suppress).
+const MAX_INTERVAL_DEVIATION_FRACTION: f32 = 0.2;
+
+/// Tap count of the adaptive motion-noise canceller.
+const MOTION_TAPS: usize = 6;
+/// Step size of the normalized-LMS weight update.
+const MOTION_MU: f32 = 0.01;
+/// Added to the reference energy before normalizing, to avoid divide-by-zero.
+const MOTION_EPS: f32 = 1.0;
+
+/// Normalized-LMS adaptive filter that estimates the motion-correlated
+/// component of the PPG signal from an external reference (e.g.
+/// accelerometer magnitude) and cancels it.
+#[derive(Debug)]
+struct MotionCanceller {
+    weights: [f32; MOTION_TAPS],
+    ref_history: [f32; MOTION_TAPS],
+}
+
+impl MotionCanceller {
+    fn new() -> Self {
+        Self {
+            weights: [0.0; MOTION_TAPS],
+            ref_history: [0.0; MOTION_TAPS],
+        }
+    }
+
+    /// Cancel the motion-correlated component of `ppg`, using `accel_mag` as
+    /// the reference, and adapt the filter weights via normalized LMS.
+    fn cancel(&mut self, ppg: f32, accel_mag: i32) -> f32 {
+        for i in (1..MOTION_TAPS).rev() {
+            self.ref_history[i] = self.ref_history[i - 1];
+        }
+        self.ref_history[0] = accel_mag as f32;
+
+        let yhat: f32 = self
+            .weights
+            .iter()
+            .zip(self.ref_history.iter())
+            .map(|(w, r)| w * r)
+            .sum();
+        let error = ppg - yhat;
+
+        let energy: f32 = self.ref_history.iter().map(|r| r * r).sum();
+        let step = MOTION_MU / (MOTION_EPS + energy);
+        for (w, r) in self.weights.iter_mut().zip(self.ref_history.iter()) {
+            *w += step * error * r;
+        }
+
+        error
+    }
+}
+
+/// Allocation-free PPG processing pipeline driven at a fixed sampling
+/// cadence. Feed raw C0 samples in with [`push_sample`](Self::push_sample)
+/// and get back a BPM estimate once enough consistent beats have been
+/// observed.
+#[derive(Debug)]
+pub(crate) struct PpgPipeline {
+    sample_rate_hz: f32,
+
+    raw_ring: [u32; RING_LEN],
+    ring_head: usize,
+    ring_len: usize,
+    ring_sum: u32,
+
+    lowpass_y: f32,
+    prev_filtered: f32,
+    prev_prev_filtered: f32,
+
+    envelope: f32,
+    tick: u32,
+    samples_since_peak: u32,
+    last_peak_tick: Option<u32>,
+    intervals: [u32; MAX_INTERVALS],
+    interval_count: usize,
+
+    motion: MotionCanceller,
+}
+
+impl PpgPipeline {
+    pub fn new(sample_rate_hz: f32) -> Self {
+        Self {
+            sample_rate_hz,
+            raw_ring: [0; RING_LEN],
+            ring_head: 0,
+            ring_len: 0,
+            ring_sum: 0,
+            lowpass_y: 0.0,
+            prev_filtered: 0.0,
+            prev_prev_filtered: 0.0,
+            envelope: 0.0,
+            tick: 0,
+            samples_since_peak: 0,
+            last_peak_tick: None,
+            intervals: [0; MAX_INTERVALS],
+            interval_count: 0,
+            motion: MotionCanceller::new(),
+        }
+    }
+
+    /// Push a new raw C0 sample and return a BPM estimate if one can be
+    /// derived from the data observed so far.
+    pub fn push_sample(&mut self, raw: u32) -> Option<u16> {
+        let filtered = self.filter_raw(raw);
+        self.finish_sample(filtered)
+    }
+
+    /// Push a new raw C0 sample along with an accelerometer magnitude
+    /// reference, canceling the motion-correlated component before peak
+    /// detection. Keeps a usable pulse while the wrist is moving.
+    pub fn push_sample_with_motion(&mut self, raw: u32, accel_mag: i32) -> Option<u16> {
+        let filtered = self.filter_raw(raw);
+        let cleaned = self.motion.cancel(filtered, accel_mag);
+        self.finish_sample(cleaned)
+    }
+
+    /// Remove the slow-moving DC/ambient baseline (a running mean over the
+    /// ring buffer's window) and band-limit the result with a low-pass
+    /// filter.
+    fn filter_raw(&mut self, raw: u32) -> f32 {
+        self.tick = self.tick.wrapping_add(1);
+        self.push_raw(raw);
+
+        let hp = raw as f32 - self.baseline_mean();
+        self.lowpass_y += LOWPASS_ALPHA * (hp - self.lowpass_y);
+        self.lowpass_y
+    }
+
+    fn push_raw(&mut self, raw: u32) {
+        if self.ring_len == RING_LEN {
+            self.ring_sum -= self.raw_ring[self.ring_head];
+        } else {
+            self.ring_len += 1;
+        }
+        self.raw_ring[self.ring_head] = raw;
+        self.ring_sum += raw;
+        self.ring_head = (self.ring_head + 1) % RING_LEN;
+    }
+
+    fn baseline_mean(&self) -> f32 {
+        self.ring_sum as f32 / self.ring_len as f32
+    }
+
+    fn finish_sample(&mut self, filtered: f32) -> Option<u16> {
+        let bpm = self.detect_and_estimate(filtered);
+
+        self.prev_prev_filtered = self.prev_filtered;
+        self.prev_filtered = filtered;
+        self.samples_since_peak = self.samples_since_peak.saturating_add(1);
+
+        bpm
+    }
+
+    fn detect_and_estimate(&mut self, filtered: f32) -> Option<u16> {
+        let abs_val = if filtered < 0.0 { -filtered } else { filtered };
+        self.envelope = if abs_val > self.envelope {
+            abs_val
+        } else {
+            self.envelope * ENVELOPE_DECAY
+        };
+
+        // a peak is a local maximum of the *previous* sample that clears the
+        // adaptive threshold and is outside the refractory period
+        let is_local_max =
+            self.prev_filtered > self.prev_prev_filtered && self.prev_filtered > filtered;
+        let crosses_threshold = self.prev_filtered > self.envelope * PEAK_THRESHOLD_FRACTION;
+        let refractory_samples = (REFRACTORY_MS / 1000.0 * self.sample_rate_hz) as u32;
+        let past_refractory = self.samples_since_peak >= refractory_samples;
+
+        if self.envelope > 0.0 && is_local_max && crosses_threshold && past_refractory {
+            self.on_peak_detected();
+            self.samples_since_peak = 0;
+        }
+
+        self.estimate_bpm()
+    }
+
+    fn on_peak_detected(&mut self) {
+        // the local maximum was the previous sample, so the peak's tick is
+        // one behind the current one
+        let peak_tick = self.tick.wrapping_sub(1);
+        if let Some(last_tick) = self.last_peak_tick {
+            let interval = peak_tick.wrapping_sub(last_tick);
+            if self.interval_count < MAX_INTERVALS {
+                self.intervals[self.interval_count] = interval;
+                self.interval_count += 1;
+            } else {
+                self.intervals.copy_within(1.., 0);
+                self.intervals[MAX_INTERVALS - 1] = interval;
+            }
+        }
+        self.last_peak_tick = Some(peak_tick);
+    }
+
+    fn estimate_bpm(&self) -> Option<u16> {
+        if self.interval_count < MIN_CONSISTENT_INTERVALS {
+            return None;
+        }
+
+        let mut samples = [0u32; MAX_INTERVALS];
+        samples[..self.interval_count].copy_from_slice(&self.intervals[..self.interval_count]);
+        let used = &mut samples[..self.interval_count];
+        used.sort_unstable();
+        let median_samples = used[used.len() / 2];
+        if median_samples == 0 {
+            return None;
+        }
+
+        // Reject the set if any interval strays too far from the median —
+        // "N intervals exist" isn't the same as "N intervals agree".
+        let max_deviation = median_samples as f32 * MAX_INTERVAL_DEVIATION_FRACTION;
+        let all_consistent = used
+            .iter()
+            .all(|&interval| (interval as f32 - median_samples as f32).abs() <= max_deviation);
+        if !all_consistent {
+            return None;
+        }
+
+        let seconds_per_beat = median_samples as f32 / self.sample_rate_hz;
+        let bpm = 60.0 / seconds_per_beat;
+        if (MIN_BPM..=MAX_BPM).contains(&bpm) {
+            Some(bpm as u16)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SAMPLE_RATE_HZ: f32 = 20.0;
+
+    /// Push a triangular periodic signal (baseline + ramp up/down each
+    /// `cycle_len` samples) through `pipeline` and return the last BPM
+    /// estimate produced, if any.
+    fn push_triangle_wave(
+        pipeline: &mut PpgPipeline,
+        cycle_len: usize,
+        cycles: usize,
+    ) -> Option<u16> {
+        let half = cycle_len / 2;
+        const BASELINE: u32 = 2000;
+        const AMPLITUDE_STEP: u32 = 100;
+
+        let mut last_bpm = None;
+        for n in 0..cycle_len * cycles {
+            let i = n % cycle_len;
+            let tri = if i <= half { i } else { cycle_len - i } as u32;
+            let raw = BASELINE + tri * AMPLITUDE_STEP;
+            if let Some(bpm) = pipeline.push_sample(raw) {
+                last_bpm = Some(bpm);
+            }
+        }
+        last_bpm
+    }
+
+    #[test]
+    fn reports_no_bpm_before_enough_consistent_beats() {
+        let mut pipeline = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        // Two cycles produce at most one interval, short of
+        // MIN_CONSISTENT_INTERVALS.
+        assert_eq!(push_triangle_wave(&mut pipeline, 20, 2), None);
+    }
+
+    #[test]
+    fn estimates_bpm_from_a_steady_periodic_signal() {
+        let mut pipeline = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        // 20-sample period at 20Hz is a 1s beat-to-beat interval, i.e. 60 BPM.
+        let bpm = push_triangle_wave(&mut pipeline, 20, 10);
+        assert_eq!(bpm, Some(60));
+    }
+
+    #[test]
+    fn estimates_higher_bpm_from_a_faster_periodic_signal() {
+        let mut pipeline = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        // 10-sample period at 20Hz is a 0.5s beat-to-beat interval, i.e. 120 BPM.
+        let bpm = push_triangle_wave(&mut pipeline, 10, 12);
+        assert_eq!(bpm, Some(120));
+    }
+
+    #[test]
+    fn rejects_inconsistent_intervals() {
+        let mut pipeline = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        // A handful of steady beats establish a baseline envelope/interval...
+        push_triangle_wave(&mut pipeline, 20, 5);
+        // ...then directly tamper with the recorded intervals so they no
+        // longer agree, simulating a spurious threshold-crossing.
+        pipeline.intervals = [20, 20, 80, 0];
+        pipeline.interval_count = 3;
+        assert_eq!(pipeline.estimate_bpm(), None);
+    }
+
+    /// Deterministic, non-periodic-looking accelerometer reference (a small
+    /// LCG), so the canceller's lagged reference taps aren't linearly
+    /// dependent the way a short periodic sequence's would be.
+    fn pseudo_accel_mag(state: &mut u32) -> i32 {
+        *state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345) & 0x7fff_ffff;
+        (*state % 11) as i32 - 5
+    }
+
+    #[test]
+    fn motion_canceller_converges_on_fully_correlated_noise() {
+        let mut canceller = MotionCanceller::new();
+        let mut state = 12345u32;
+        let mut last_error = 0.0;
+        // Drive the canceller with a reference that fully explains the
+        // "PPG" signal (ppg = 4 * accel_mag); a converged NLMS filter should
+        // predict it away, leaving ~0 residual error.
+        for _ in 0..5000 {
+            let accel_mag = pseudo_accel_mag(&mut state);
+            let ppg = 4.0 * accel_mag as f32;
+            last_error = canceller.cancel(ppg, accel_mag);
+        }
+        assert!(
+            last_error.abs() < 0.1,
+            "expected motion canceller to converge, last error was {last_error}"
+        );
+    }
+
+    #[test]
+    fn recovers_bpm_from_a_signal_corrupted_by_correlated_motion_noise() {
+        const CYCLE_LEN: usize = 20;
+        const CYCLES: usize = 20;
+        const BASELINE: i32 = 2000;
+        const AMPLITUDE_STEP: i32 = 100;
+        const NOISE_GAIN: i32 = 300;
+
+        // Same steady heartbeat signal as `estimates_bpm_from_a_steady_periodic_signal`,
+        // but with a large motion artifact added that's linearly correlated
+        // with an accelerometer reference.
+        let half = CYCLE_LEN / 2;
+        let mut plain = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        let mut motion_compensated = PpgPipeline::new(TEST_SAMPLE_RATE_HZ);
+        let mut plain_bpm = None;
+        let mut compensated_bpm = None;
+        let mut state = 12345u32;
+        for n in 0..CYCLE_LEN * CYCLES {
+            let i = n % CYCLE_LEN;
+            let tri = if i <= half { i } else { CYCLE_LEN - i } as i32;
+            let accel_mag = pseudo_accel_mag(&mut state);
+            let noisy_raw =
+                (BASELINE + tri * AMPLITUDE_STEP + NOISE_GAIN * accel_mag).max(0) as u32;
+
+            if let Some(bpm) = plain.push_sample(noisy_raw) {
+                plain_bpm = Some(bpm);
+            }
+            if let Some(bpm) = motion_compensated.push_sample_with_motion(noisy_raw, accel_mag) {
+                compensated_bpm = Some(bpm);
+            }
+        }
+
+        // Uncancelled, the motion artifact swamps the heartbeat and the
+        // pipeline never settles on a consistent BPM.
+        assert_eq!(plain_bpm, None);
+        // With the correlated reference, the canceller adapts it away and
+        // the true 60 BPM heartbeat is recovered.
+        assert_eq!(compensated_bpm, Some(60));
+    }
+}